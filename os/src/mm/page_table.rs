@@ -3,13 +3,57 @@
 use core::mem;
 
 use super::{frame_alloc, FrameTracker, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::sync::UPSafeCell;
+use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use lazy_static::lazy_static;
+
+/// TLB (Translation Lookaside Buffer) maintenance.
+///
+/// `PageTable::map`/`unmap` mutate PTEs directly, but the MMU may have
+/// already cached the old translation, so every mutation needs a matching
+/// invalidation. This module wraps the local `sfence.vma` instruction plus,
+/// for SMP, an SBI call that shoots the same translation down on other
+/// harts.
+pub mod tlb {
+    use super::VirtPageNum;
+    use core::arch::asm;
+
+    /// Invalidate the local hart's cached translation for `vpn` under `asid`,
+    /// leaving other address spaces' entries untouched.
+    pub fn flush_va(vpn: VirtPageNum, asid: u16) {
+        let va = vpn.0 << 12;
+        unsafe {
+            asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid as usize);
+        }
+    }
+
+    /// Invalidate every TLB entry on the local hart, across all address
+    /// spaces. Nothing in this file calls it: a space that got no ASID of its
+    /// own uses ASID `0` with the per-page `flush_va` instead.
+    pub fn flush_all() {
+        unsafe {
+            asm!("sfence.vma");
+        }
+    }
+
+    /// Shoot down the translations covering `[va, va + size)` on the harts
+    /// in `hart_mask`, so an unmap performed on this hart is observed by the
+    /// others instead of leaving them with a stale TLB entry.
+    ///
+    /// Takes a whole `(va, size)` range rather than a single page so callers
+    /// can batch a multi-page unmap into one SBI call instead of one IPI per
+    /// page.
+    pub fn remote_flush(hart_mask: sbi_rt::HartMask, va: usize, size: usize) {
+        sbi_rt::remote_sfence_vma(hart_mask, va, size);
+    }
+}
 
 bitflags! {
     /// page table entry flags
-    pub struct PTEFlags: u8 {
+    pub struct PTEFlags: u16 {
         const V = 1 << 0;
         const R = 1 << 1;
         const W = 1 << 2;
@@ -18,6 +62,11 @@ bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        /// Software-reserved bit 0 (PTE bit 8, RSW). Marks a page that is
+        /// shared copy-on-write with another address space: the PTE is
+        /// mapped without `W`, and a store to it should go through
+        /// [`PageTable::handle_store_fault`] instead of faulting for real.
+        const COW = 1 << 8;
     }
 }
 
@@ -46,7 +95,9 @@ impl PageTableEntry {
     }
     /// Get the flags from the page table entry
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
+        // bits 9..0 hold V/R/W/X/U/G/A/D plus the two RSW (software-reserved)
+        // bits, one of which backs `PTEFlags::COW`
+        PTEFlags::from_bits((self.bits & 0x3ff) as u16).unwrap()
     }
     /// The page pointered by page table entry is valid?
     pub fn is_valid(&self) -> bool {
@@ -64,22 +115,209 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// Is this PTE itself a leaf (a megapage/gigapage), rather than a
+    /// pointer to the next page-table level? Per the Sv39/Sv48 rule, any
+    /// PTE with R, W or X set is a leaf, wherever it appears in the walk.
+    pub fn is_leaf(&self) -> bool {
+        (self.flags() & (PTEFlags::R | PTEFlags::W | PTEFlags::X)) != PTEFlags::empty()
+    }
+    /// Is this page shared copy-on-write? See [`PTEFlags::COW`].
+    pub fn is_cow(&self) -> bool {
+        (self.flags() & PTEFlags::COW) != PTEFlags::empty()
+    }
+}
+
+/// Width in bits of the ASID field (satp bits 59..44).
+const ASID_BITS: usize = 16;
+/// Largest ASID value the field can hold.
+const ASID_MAX: u16 = ((1usize << ASID_BITS) - 1) as u16;
+
+/// A recycling allocator for Address Space Identifiers.
+///
+/// Tagging a page table with an ASID lets the hardware cache its
+/// translations alongside every other space's instead of forcing a full
+/// flush on every `satp` switch. IDs are handed out from an incrementing
+/// counter and reclaimed into a free list on `dealloc`; once the counter
+/// reaches `ASID_MAX` with nothing recycled, `alloc` returns `None`
+/// (`ASID_MAX` itself is never handed out). `asid_alloc` turns that into
+/// ASID `0`; nothing in this file issues a full TLB flush for such a space.
+struct AsidAllocator {
+    current: u16,
+    recycled: Vec<u16>,
+}
+
+impl AsidAllocator {
+    pub fn new() -> Self {
+        Self {
+            // `0` is reserved to mean "untagged" (see `asid_alloc`), so the
+            // counter starts at `1` and never hands it out as a real ASID.
+            current: 1,
+            recycled: Vec::new(),
+        }
+    }
+    pub fn alloc(&mut self) -> Option<u16> {
+        if let Some(asid) = self.recycled.pop() {
+            Some(asid)
+        } else if self.current < ASID_MAX {
+            let asid = self.current;
+            self.current += 1;
+            Some(asid)
+        } else {
+            None
+        }
+    }
+    pub fn dealloc(&mut self, asid: u16) {
+        self.recycled.push(asid);
+    }
+}
+
+lazy_static! {
+    static ref ASID_ALLOCATOR: UPSafeCell<AsidAllocator> =
+        unsafe { UPSafeCell::new(AsidAllocator::new()) };
+}
+
+/// Allocate a fresh ASID for a new address space, or `0` if the 16-bit
+/// space has been exhausted. `0` is never handed out by the allocator
+/// itself, so reusing it here just means "treat this space as untagged"
+/// rather than colliding with a real address space.
+fn asid_alloc() -> u16 {
+    ASID_ALLOCATOR.exclusive_access().alloc().unwrap_or(0)
+}
+
+lazy_static! {
+    /// Reference counts for frames shared copy-on-write between address
+    /// spaces, keyed by physical page number. A frame absent from this map
+    /// has exactly one owner; `FrameTracker`'s `Drop` impl should consult it
+    /// and, while the count is still above one, decrement it instead of
+    /// freeing the frame for real.
+    static ref COW_REFCOUNT: UPSafeCell<BTreeMap<usize, usize>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+/// Record a new shared owner of `ppn`. The first share bumps the implicit
+/// count of 1 (the original owner) up to 2.
+fn cow_share(ppn: PhysPageNum) {
+    let mut table = COW_REFCOUNT.exclusive_access();
+    *table.entry(ppn.0).or_insert(1) += 1;
+}
+
+/// Current number of owners sharing `ppn` (1 if it isn't currently shared).
+fn cow_refcount(ppn: PhysPageNum) -> usize {
+    *COW_REFCOUNT.exclusive_access().get(&ppn.0).unwrap_or(&1)
+}
+
+/// Drop one shared owner of `ppn`, clearing its bookkeeping entry once only
+/// a single owner remains (at which point it is no longer "shared").
+fn cow_unshare(ppn: PhysPageNum) {
+    let mut table = COW_REFCOUNT.exclusive_access();
+    if let Some(count) = table.get_mut(&ppn.0) {
+        *count -= 1;
+        if *count <= 1 {
+            table.remove(&ppn.0);
+        }
+    }
+}
+
+/// Called from `FrameTracker`'s `Drop` impl (see `frame_allocator.rs`)
+/// before a frame would otherwise be freed. If `ppn` is still shared
+/// copy-on-write, this just decrements the share count and reports that
+/// the frame must stay alive; only once the last owner drops it does it
+/// return `true`, telling the caller it's safe to return the frame to the
+/// allocator for real. This is what actually ties `COW_REFCOUNT` to frame
+/// lifetime, instead of leaving it a bookkeeping table nobody consults.
+pub fn cow_drop(ppn: PhysPageNum) -> bool {
+    let mut table = COW_REFCOUNT.exclusive_access();
+    match table.get_mut(&ppn.0) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            if *count <= 1 {
+                table.remove(&ppn.0);
+            }
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Which RISC-V multi-level paging scheme a [`PageTable`] walks.
+///
+/// The two schemes differ only in how many 9-bit VPN fields make up a
+/// virtual address and what nibble `satp.MODE` must hold to select them;
+/// everything else about the walk is identical.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    /// 3-level page tables, 39-bit virtual addresses (512 GiB).
+    Sv39,
+    /// 4-level page tables, 48-bit virtual addresses (256 TiB).
+    Sv48,
+}
+
+impl PagingMode {
+    /// Number of page-table levels this mode walks (and the number of
+    /// 9-bit VPN fields a [`VirtPageNum`] is split into).
+    fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+    /// The `MODE` nibble (satp bits 63..60) that selects this scheme.
+    fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+        }
+    }
+    /// Recover the mode from a `satp` value's MODE nibble, defaulting to
+    /// Sv39 for anything else (e.g. a zeroed `satp` read before a real one
+    /// has been installed).
+    fn from_satp(satp: usize) -> Self {
+        match satp >> 60 {
+            9 => PagingMode::Sv48,
+            _ => PagingMode::Sv39,
+        }
+    }
+}
+
+/// Split `vpn` into `levels` 9-bit VPN fields, highest level first — the
+/// generalization of the fixed 3-field Sv39 layout to any walk depth.
+fn vpn_indexes(vpn: VirtPageNum, levels: usize) -> Vec<usize> {
+    let mut bits = vpn.0;
+    let mut idxs = vec![0usize; levels];
+    for i in (0..levels).rev() {
+        idxs[i] = bits & 0x1ff;
+        bits >>= 9;
+    }
+    idxs
 }
 
 /// page table structure
 pub struct PageTable {
     root_ppn: PhysPageNum,
     frames: Vec<FrameTracker>,
+    /// Address Space Identifier tagging this table's TLB entries, encoded
+    /// into bits 59..44 of the `satp` token returned by [`PageTable::token`].
+    asid: u16,
+    /// The paging scheme this table is walked with (Sv39 or Sv48).
+    mode: PagingMode,
+    /// Whether this table owns `asid` and must return it to
+    /// [`ASID_ALLOCATOR`] on drop. `false` for the transient tables
+    /// reconstructed by [`PageTable::from_token`], which only borrow the
+    /// ASID of a table that actually owns it.
+    owns_asid: bool,
 }
 
 /// Assume that it won't oom when creating/mapping.
 impl PageTable {
-    /// Create a new page table
-    pub fn new() -> Self {
+    /// Create a new page table for the given paging scheme.
+    pub fn new(mode: PagingMode) -> Self {
         let frame = frame_alloc().unwrap();
         PageTable {
             root_ppn: frame.ppn,
             frames: vec![frame],
+            asid: asid_alloc(),
+            mode,
+            owns_asid: true,
         }
     }
     /// Temporarily used to get arguments from user space.
@@ -87,16 +325,24 @@ impl PageTable {
         Self {
             root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
             frames: Vec::new(),
+            asid: ((satp >> 44) & ((1usize << ASID_BITS) - 1)) as u16,
+            mode: PagingMode::from_satp(satp),
+            owns_asid: false,
         }
     }
+    /// This table's Address Space Identifier.
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
     /// Find PageTableEntry by VirtPageNum, create a frame for a 4KB page table if not exist
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-        let idxs = vpn.indexes();
+        let idxs = vpn_indexes(vpn, self.mode.levels());
+        let leaf = self.mode.levels() - 1;
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == leaf {
                 result = Some(pte);
                 break;
             }
@@ -105,18 +351,24 @@ impl PageTable {
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
+            assert!(
+                !pte.is_leaf(),
+                "vpn {:?} falls inside an existing huge page mapping",
+                vpn
+            );
             ppn = pte.ppn();
         }
         result
     }
     /// Find PageTableEntry by VirtPageNum
     fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-        let idxs = vpn.indexes();
+        let idxs = vpn_indexes(vpn, self.mode.levels());
+        let leaf = self.mode.levels() - 1;
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == leaf || pte.is_leaf() {
                 result = Some(pte);
                 break;
             }
@@ -133,6 +385,7 @@ impl PageTable {
         let pte = self.find_pte_create(vpn).unwrap();
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        tlb::flush_va(vpn, self.asid);
     }
     /// remove the map between virtual page number and physical page number
     #[allow(unused)]
@@ -140,10 +393,240 @@ impl PageTable {
         let pte = self.find_pte(vpn).unwrap();
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PageTableEntry::empty();
+        tlb::flush_va(vpn, self.asid);
+        // Other harts may have this translation cached too, so an unmap
+        // needs a shootdown, not just a local sfence.vma.
+        tlb::remote_flush(sbi_rt::HartMask::all(), vpn.0 << 12, 1 << 12);
+    }
+    /// Map a huge page: a megapage (`level` = `levels() - 2`, 2 MiB) or a
+    /// gigapage (`level` = `levels() - 3`, 1 GiB; `0` under Sv39). Under
+    /// Sv48, `level` = `0` selects a 512 GiB page. The walk stops at `level`
+    /// instead of descending to the bottom, and per the Sv39/Sv48 rule that
+    /// any PTE with R/W/X set is itself a leaf, the mapping's PTE is
+    /// written there directly. `vpn` and `ppn` must both be aligned to the
+    /// page size `level` selects.
+    pub fn map_huge(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags, level: usize) {
+        let leaf_levels = self.mode.levels();
+        assert!(
+            level < leaf_levels - 1,
+            "level {} leaves no room for a huge page",
+            level
+        );
+        let align_bits = 9 * (leaf_levels - 1 - level);
+        assert_eq!(
+            vpn.0 & ((1usize << align_bits) - 1),
+            0,
+            "vpn {:?} is not aligned for a level-{} huge page",
+            vpn,
+            level
+        );
+        assert_eq!(
+            ppn.0 & ((1usize << align_bits) - 1),
+            0,
+            "ppn {:?} is not aligned for a level-{} huge page",
+            ppn,
+            level
+        );
+
+        let idxs = vpn_indexes(vpn, leaf_levels);
+        let mut ppn_walk = self.root_ppn;
+        for (i, idx) in idxs.iter().enumerate() {
+            let pte = &mut ppn_walk.get_pte_array()[*idx];
+            if i == level {
+                assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
+                *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+                tlb::flush_va(vpn, self.asid);
+                return;
+            }
+            if !pte.is_valid() {
+                let frame = frame_alloc().unwrap();
+                *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
+                self.frames.push(frame);
+            }
+            ppn_walk = pte.ppn();
+        }
+    }
+    /// Find the PTE translating `vpn`, together with the page-table level
+    /// it was found at. The walk stops as soon as it meets a leaf PTE
+    /// (R/W/X set), which may be above the bottom level for a huge page.
+    fn find_pte_with_level(&self, vpn: VirtPageNum) -> Option<(PageTableEntry, usize)> {
+        let leaf_levels = self.mode.levels();
+        let idxs = vpn_indexes(vpn, leaf_levels);
+        let mut ppn = self.root_ppn;
+        for (i, idx) in idxs.iter().enumerate() {
+            let pte = ppn.get_pte_array()[*idx];
+            if !pte.is_valid() {
+                return None;
+            }
+            if i == leaf_levels - 1 || pte.is_leaf() {
+                return Some((pte, i));
+            }
+            ppn = pte.ppn();
+        }
+        None
     }
     /// get the page table entry from the virtual page number
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).map(|pte| *pte)
+        self.find_pte_with_level(vpn).map(|(pte, _)| pte)
+    }
+    /// Resolve `va` to the PTE mapping it, together with the physical page
+    /// number of the 4 KiB frame containing `va`. For a huge-page leaf
+    /// above the bottom level, `pte.ppn()` is only the *base* PPN of the
+    /// whole megapage/gigapage, so the low bits of `va` that the leaf's
+    /// level doesn't cover are folded in to recover the actual containing
+    /// frame, instead of assuming `pte.ppn()` already is a 4 KiB page.
+    fn resolve_page(&self, va: VirtAddr) -> Option<(PageTableEntry, PhysPageNum)> {
+        let leaf_levels = self.mode.levels();
+        let (pte, level) = self.find_pte_with_level(va.floor())?;
+        let offset_bits = 12 + 9 * (leaf_levels - 1 - level);
+        let page_pa = pte.ppn().0 << 12;
+        let mask = (1usize << offset_bits) - 1;
+        let pa = (page_pa & !mask) | (va.0 & mask);
+        Some((pte, PhysPageNum::from(pa >> 12)))
+    }
+    /// Translate a full virtual address to its physical address, resolving
+    /// through a huge-page leaf at any level instead of assuming a 4 KiB
+    /// bottom-level mapping.
+    pub fn translate_va(&self, va: VirtAddr) -> Option<usize> {
+        let (_, ppn) = self.resolve_page(va)?;
+        Some((ppn.0 << 12) | va.page_offset())
+    }
+    /// Map `vpn` to `ppn` with `flags` (already including `V`) as-is,
+    /// without asserting the slot was previously empty. Used to populate a
+    /// freshly created child table in [`PageTable::clone_cow`], which never
+    /// has anything mapped there yet but isn't the normal `map` path.
+    fn map_raw(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        let pte = self.find_pte_create(vpn).unwrap();
+        *pte = PageTableEntry::new(ppn, flags);
+    }
+    /// Walk every valid leaf PTE in this table, from its root, calling
+    /// `f(vpn, pte, level)` for each one found. `level` is the bottom level
+    /// (`self.mode.levels() - 1`) for an ordinary 4 KiB page, or a
+    /// shallower level for a huge-page leaf.
+    fn for_each_leaf(&self, mut f: impl FnMut(VirtPageNum, PageTableEntry, usize)) {
+        fn walk(
+            ppn: PhysPageNum,
+            level: usize,
+            leaf_levels: usize,
+            vpn_prefix: usize,
+            f: &mut impl FnMut(VirtPageNum, PageTableEntry, usize),
+        ) {
+            for (idx, pte) in ppn.get_pte_array().iter().enumerate() {
+                if !pte.is_valid() {
+                    continue;
+                }
+                let vpn_prefix = (vpn_prefix << 9) | idx;
+                if level == leaf_levels - 1 || pte.is_leaf() {
+                    let shift = 9 * (leaf_levels - 1 - level);
+                    f(VirtPageNum::from(vpn_prefix << shift), *pte, level);
+                } else {
+                    walk(pte.ppn(), level + 1, leaf_levels, vpn_prefix, f);
+                }
+            }
+        }
+        walk(self.root_ppn, 0, self.mode.levels(), 0, &mut f);
+    }
+    /// Populate `child` so it shares this table's writable frames
+    /// copy-on-write instead of deep-copying them, for `fork`.
+    ///
+    /// Every writable leaf has its `W` bit cleared and [`PTEFlags::COW`] set
+    /// in *both* tables — the parent's own mapping is downgraded too, since
+    /// leaving it writable would let the parent silently corrupt memory the
+    /// child believes is COW-protected — and the underlying frame's
+    /// reference count is bumped. A leaf that is *already* COW (this isn't
+    /// the lineage's first fork) is shared the same way even though its `W`
+    /// bit is already clear: the refcount still needs bumping and the new
+    /// child still needs its own [`FrameTracker`], or the frame gets freed
+    /// out from under it the moment some earlier owner drops. Leaves that
+    /// are neither writable nor COW (genuinely shared, e.g. the trampoline)
+    /// are mapped into the child unchanged.
+    ///
+    /// # Panics
+    /// If a writable or already-COW leaf is a huge page (a megapage or
+    /// gigapage from [`PageTable::map_huge`]). `cow_share`/`FrameTracker`
+    /// assume a shared `ppn` is a single `frame_alloc`-backed 4 KiB frame,
+    /// and [`PageTable::handle_store_fault`] only ever copies one 4 KiB
+    /// frame on a COW fault — sharing a huge leaf this way would alias or
+    /// corrupt every other address inside it. Huge mappings aren't
+    /// COW-fork-able yet; fork a process without them instead.
+    pub fn clone_cow(&mut self, child: &mut PageTable) {
+        let leaf_levels = self.mode.levels();
+        let mut entries = Vec::new();
+        self.for_each_leaf(|vpn, pte, level| entries.push((vpn, pte, level)));
+        for (vpn, pte, level) in entries {
+            let ppn = pte.ppn();
+            let mut flags = pte.flags();
+            if flags.contains(PTEFlags::W) || pte.is_cow() {
+                assert_eq!(
+                    level,
+                    leaf_levels - 1,
+                    "vpn {:?} is a huge page leaf at level {}; huge pages can't be COW-shared",
+                    vpn,
+                    level
+                );
+                if flags.contains(PTEFlags::W) {
+                    flags.remove(PTEFlags::W);
+                    flags.insert(PTEFlags::COW);
+                    *self.find_pte(vpn).unwrap() = PageTableEntry::new(ppn, flags);
+                    tlb::flush_va(vpn, self.asid);
+                }
+                cow_share(ppn);
+                // Give the child its own RAII owner of the shared frame so
+                // the allocator only gets it back once every `FrameTracker`
+                // pointing at it (parent's original one included) has
+                // dropped — see `cow_drop` and `FrameTracker::shared`.
+                child.frames.push(FrameTracker::shared(ppn));
+            }
+            if level == leaf_levels - 1 {
+                child.map_raw(vpn, ppn, flags);
+            } else {
+                child.map_huge(vpn, ppn, flags, level);
+            }
+        }
+    }
+    /// Resolve a store page fault on `vpn`, per the copy-on-write protocol
+    /// set up by [`PageTable::clone_cow`].
+    ///
+    /// If the frame backing `vpn` is still shared (`refcount > 1`), a fresh
+    /// frame is allocated, the 4 KiB contents copied over, and the faulting
+    /// page remapped writable onto the copy; if this was already the last
+    /// owner, the page is simply made writable again in place. Returns
+    /// `false` if `vpn` isn't a COW page, so the trap handler can fall
+    /// through to its normal invalid-store handling.
+    ///
+    /// Always copies exactly one 4 KiB frame: [`PageTable::clone_cow`]
+    /// never marks a huge-page leaf COW, so a COW `vpn` is always a
+    /// bottom-level mapping here.
+    pub fn handle_store_fault(&mut self, vpn: VirtPageNum) -> bool {
+        let pte = match self.find_pte(vpn) {
+            Some(pte) if pte.is_cow() => *pte,
+            _ => return false,
+        };
+        let old_ppn = pte.ppn();
+        let mut flags = pte.flags();
+        flags.remove(PTEFlags::COW);
+        flags.insert(PTEFlags::W);
+        if cow_refcount(old_ppn) > 1 {
+            let frame = frame_alloc().unwrap();
+            let new_ppn = frame.ppn;
+            new_ppn
+                .get_bytes_array()
+                .copy_from_slice(old_ppn.get_bytes_array());
+            self.frames.push(frame);
+            *self.find_pte(vpn).unwrap() = PageTableEntry::new(new_ppn, flags);
+            // Give up a share only if this table holds an owner of the old
+            // frame: take that `FrameTracker` out without running its drop
+            // and unshare in its place. Otherwise the count is left as is,
+            // and the frame lives until its owner elsewhere drops.
+            if let Some(i) = self.frames.iter().position(|f| f.ppn.0 == old_ppn.0) {
+                mem::forget(self.frames.swap_remove(i));
+                cow_unshare(old_ppn);
+            }
+        } else {
+            *self.find_pte(vpn).unwrap() = PageTableEntry::new(old_ppn, flags);
+        }
+        tlb::flush_va(vpn, self.asid);
+        true
     }
     /// get the token from the page table
     /// RISC-V 64位架构中的 `satp` 寄存器（Supervisor Address Translation and Protection Register）的位布局:
@@ -158,8 +641,23 @@ impl PageTable {
     ///
     /// 所以这个函数的作用就是将页表根目录的物理页号转换成一个符合 satp 寄存器规范的值
     pub fn token(&self) -> usize {
-        // 8usize << 60 -> set MODE = 8 -> open Sv39
-        8usize << 60 | self.root_ppn.0
+        // MODE = 8 for Sv39, 9 for Sv48, taken from self.mode
+        // self.asid << 44 -> tag every entry this table creates with its own
+        // ASID, so switching satp doesn't force a full TLB flush
+        self.mode.satp_mode() << 60 | (self.asid as usize) << 44 | self.root_ppn.0
+    }
+}
+
+impl Drop for PageTable {
+    /// Return an owned ASID to [`ASID_ALLOCATOR`] so long-running systems
+    /// don't leak the 16-bit space as address spaces are torn down. Tables
+    /// built by [`PageTable::from_token`] only borrow an ASID they don't
+    /// own, and `0` is never a real allocation (see `asid_alloc`), so
+    /// neither is returned here.
+    fn drop(&mut self) {
+        if self.owns_asid && self.asid != 0 {
+            ASID_ALLOCATOR.exclusive_access().dealloc(self.asid);
+        }
     }
 }
 
@@ -172,7 +670,7 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     while start < end {
         let start_va = VirtAddr::from(start);
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        let (_, ppn) = page_table.resolve_page(start_va).unwrap();
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -232,3 +730,111 @@ pub fn convert_from_buffer<T>(buffers: Vec<&[u8]>) -> T {
         ptr.read()
     }
 }
+
+/// Why translating a user-space pointer failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFault {
+    /// No PTE maps the faulting virtual address.
+    Unmapped {
+        /// The virtual address that produced the fault.
+        va: usize,
+    },
+    /// A PTE maps the address, but not with the permission the access
+    /// needed (e.g. a write through a non-writable PTE).
+    PermissionDenied {
+        /// The virtual address that produced the fault.
+        va: usize,
+    },
+    /// The buffer is long enough, but not aligned the way `T` requires.
+    Misaligned {
+        /// The virtual address that produced the fault.
+        va: usize,
+    },
+}
+
+/// Fallible counterpart to [`translated_byte_buffer`]: returns a
+/// [`PageFault`] instead of panicking when a page is unmapped, or (when
+/// `write` is set) mapped without `W`.
+pub fn try_translated_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+    write: bool,
+) -> Result<Vec<&'static mut [u8]>, PageFault> {
+    let page_table = PageTable::from_token(token);
+    let mut start = ptr as usize;
+    let end = start + len;
+    let mut v = Vec::new();
+    while start < end {
+        let start_va = VirtAddr::from(start);
+        let mut vpn = start_va.floor();
+        let (pte, ppn) = page_table
+            .resolve_page(start_va)
+            .ok_or(PageFault::Unmapped { va: start })?;
+        if write && !pte.writable() {
+            return Err(PageFault::PermissionDenied { va: start });
+        }
+        vpn.step();
+        let mut end_va: VirtAddr = vpn.into();
+        end_va = end_va.min(VirtAddr::from(end));
+        if end_va.page_offset() == 0 {
+            v.push(&mut ppn.get_bytes_array()[start_va.page_offset()..]);
+        } else {
+            v.push(&mut ppn.get_bytes_array()[start_va.page_offset()..end_va.page_offset()]);
+        }
+        start = end_va.into();
+    }
+    Ok(v)
+}
+
+/// Fallible counterpart to [`write_translated_buffer`]: returns a
+/// [`PageFault`] instead of panicking when `ptr` isn't mapped writable.
+pub fn try_write_translated_buffer<T: Sized>(
+    token: usize,
+    ptr: *const u8,
+    val: T,
+) -> Result<(), PageFault> {
+    let buffers = try_translated_byte_buffer(token, ptr, mem::size_of::<T>(), true)?;
+    let mut val_ptr = &val as *const _ as *const u8;
+    for buffer in buffers {
+        unsafe {
+            val_ptr.copy_to(buffer.as_mut_ptr(), buffer.len());
+            val_ptr = val_ptr.add(buffer.len());
+        }
+    }
+    Ok(())
+}
+
+/// Fallible counterpart to [`translated_t`]: returns a [`PageFault`]
+/// instead of panicking on an unmapped page or a misaligned `T`.
+pub fn try_translated_t<T: Sized>(token: usize, ptr: *const u8, len: usize) -> Result<T, PageFault> {
+    let buffers = try_translated_byte_buffer(token, ptr, len, false)?;
+    let buffers: Vec<&[u8]> = buffers.iter().map(|slice| &**slice).collect();
+    try_convert_from_buffer(buffers, ptr as usize)
+}
+
+/// Fallible counterpart to [`convert_from_buffer`]: checks the combined
+/// length and the alignment `T` requires before reading, instead of
+/// assuming both already hold.
+pub fn try_convert_from_buffer<T>(buffers: Vec<&[u8]>, va: usize) -> Result<T, PageFault> {
+    let mut combined: Vec<u8> = Vec::new();
+
+    for buffer in buffers {
+        combined.extend_from_slice(buffer);
+    }
+
+    if combined.len() < mem::size_of::<T>() {
+        return Err(PageFault::Unmapped { va });
+    }
+    // `va` is what EFAULTs for a misaligned *user* pointer; `combined` is a
+    // fresh heap allocation with no relation to it, so the actual read
+    // below must tolerate misalignment regardless of this check.
+    if va % mem::align_of::<T>() != 0 {
+        return Err(PageFault::Misaligned { va });
+    }
+
+    unsafe {
+        let ptr = combined.as_ptr() as *const T;
+        Ok(ptr.read_unaligned())
+    }
+}